@@ -0,0 +1,288 @@
+//! Reads and writes Scala keyboard mapping files (.kbm), the companion format
+//! to .scl that assigns scale degrees to MIDI keys.
+//! http://www.huygens-fokker.org/scala/help.htm#mappings
+
+use Line;
+use ParseError;
+use ParseErrorKind;
+use Scale;
+use lines_with_positions;
+
+/// A Scala keyboard mapping: assigns scale degrees to a range of MIDI keys.
+///
+/// `keys[i]` is the scale degree sounded by the `i`th key counting from
+/// `middle_note`, or `None` for a key that is left unmapped (a blank line in
+/// the file). The pattern repeats every `map_size` keys, each repetition
+/// shifted by `octave_degree` degrees. If `map_size` is `0` the mapping is
+/// linear: every key maps directly to the scale degree `key - middle_note`
+/// and `keys` is empty.
+#[derive(Debug, PartialEq, Clone)]
+pub struct KeyboardMapping {
+    pub map_size: usize,
+    pub first_note: i32,
+    pub last_note: i32,
+    pub middle_note: i32,
+    pub reference_note: i32,
+    pub reference_frequency: f64,
+    pub octave_degree: i32,
+    pub keys: Vec<Option<usize>>,
+}
+
+impl KeyboardMapping {
+    /// The scale degree sounded by `note`, or `None` if `note` falls on an
+    /// unmapped key, or outside `keys` entirely (e.g. a `KeyboardMapping`
+    /// built directly whose `keys` doesn't actually hold `map_size` entries).
+    /// Does not check `first_note`/`last_note` bounds.
+    fn degree_for(&self, note: i32) -> Option<i32> {
+        let offset = note - self.middle_note;
+
+        if self.map_size == 0 {
+            return Some(offset);
+        }
+
+        let map_size = self.map_size as i32;
+        let key_index = ((offset % map_size) + map_size) % map_size;
+        let periods = (offset - key_index) / map_size;
+
+        self.keys.get(key_index as usize).copied().flatten()
+            .map(|degree| degree as i32 + periods * self.octave_degree)
+    }
+}
+
+impl std::fmt::Display for KeyboardMapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        try!(writeln!(f, "{}", self.map_size));
+        try!(writeln!(f, "{}", self.first_note));
+        try!(writeln!(f, "{}", self.last_note));
+        try!(writeln!(f, "{}", self.middle_note));
+        try!(writeln!(f, "{}", self.reference_note));
+        try!(writeln!(f, "{}", self.reference_frequency));
+        try!(self.octave_degree.fmt(f));
+
+        for key in &self.keys {
+            try!(writeln!(f, ""));
+            if let &Some(degree) = key {
+                try!(degree.fmt(f));
+            }
+        }
+
+        Ok( () )
+    }
+}
+
+/// Consumes `lines[*idx]` and parses it as a header field, advancing `idx`.
+/// `field` names the expected content for the `MissingField`/`BadField` error.
+fn take_field<T: std::str::FromStr>(lines: &[Line], idx: &mut usize, field: &'static str)
+    -> Result<T, ParseError>
+{
+    let line = match lines.get(*idx) {
+        Some(line) => line,
+        None => {
+            let line_number = lines.last().map_or(1, |line| line.number + 1);
+            return Err(ParseError {
+                kind: ParseErrorKind::MissingField(field),
+                line: line_number,
+                span: 0..0,
+            });
+        },
+    };
+    *idx += 1;
+
+    let trimmed = line.raw.trim();
+    match trimmed.parse() {
+        Ok(value) => Ok(value),
+        Err(_) => {
+            let leading = line.raw.len() - line.raw.trim_start().len();
+            let token_start = line.start + leading;
+            Err(ParseError {
+                kind: ParseErrorKind::BadField(field),
+                line: line.number,
+                span: token_start..(token_start + trimmed.len()),
+            })
+        },
+    }
+}
+
+impl std::str::FromStr for KeyboardMapping {
+    type Err = ParseError;
+    fn from_str(kbm_string: &str) -> Result<KeyboardMapping, ParseError> {
+        let lines: Vec<Line> = lines_with_positions(kbm_string).into_iter()
+            .filter(|line| !line.raw.starts_with("!"))
+            .collect();
+        let mut idx = 0;
+
+        let map_size: usize = try!(take_field(&lines, &mut idx, "map size"));
+        let first_note: i32 = try!(take_field(&lines, &mut idx, "first MIDI note"));
+        let last_note: i32 = try!(take_field(&lines, &mut idx, "last MIDI note"));
+        let middle_note: i32 = try!(take_field(&lines, &mut idx, "middle note"));
+        let reference_note: i32 = try!(take_field(&lines, &mut idx, "reference note"));
+        let reference_frequency: f64 = try!(take_field(&lines, &mut idx, "reference frequency"));
+        let octave_degree: i32 = try!(take_field(&lines, &mut idx, "octave degree"));
+
+        let mut keys = Vec::with_capacity(map_size);
+        for line in &lines[idx..] {
+            let trimmed = line.raw.trim();
+            if trimmed.is_empty() {
+                keys.push(None);
+                continue;
+            }
+
+            match trimmed.parse() {
+                Ok(degree) => keys.push(Some(degree)),
+                Err(_) => {
+                    let leading = line.raw.len() - line.raw.trim_start().len();
+                    let token_start = line.start + leading;
+                    return Err(ParseError {
+                        kind: ParseErrorKind::BadKey,
+                        line: line.number,
+                        span: token_start..(token_start + trimmed.len()),
+                    });
+                },
+            }
+        }
+
+        if keys.len() != map_size {
+            return Err(ParseError {
+                kind: ParseErrorKind::KeyCountMismatch { expected: map_size, found: keys.len() },
+                line: lines.last().map_or(1, |line| line.number),
+                span: 0..0,
+            });
+        }
+
+        Ok(KeyboardMapping {
+            map_size: map_size,
+            first_note: first_note,
+            last_note: last_note,
+            middle_note: middle_note,
+            reference_note: reference_note,
+            reference_frequency: reference_frequency,
+            octave_degree: octave_degree,
+            keys: keys,
+        })
+    }
+}
+
+impl Scale {
+    /// The sounding frequency, in Hz, of `midi_note` under `mapping`, or
+    /// `None` if the note is outside the mapping's range or falls on an
+    /// unmapped key.
+    pub fn map_key(&self, mapping: &KeyboardMapping, midi_note: u8) -> Option<f64> {
+        let note = midi_note as i32;
+        if note < mapping.first_note || note > mapping.last_note {
+            return None;
+        }
+
+        let degree = mapping.degree_for(note)?;
+        let reference_degree = mapping.degree_for(mapping.reference_note).unwrap_or(0);
+
+        Some(mapping.reference_frequency
+            * self.frequency_at(degree, 1.0)
+            / self.frequency_at(reference_degree, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Note;
+    use Scale;
+    use kbm::KeyboardMapping;
+    use std::str::FromStr;
+
+    fn twelve_tet_mapping() -> KeyboardMapping {
+        KeyboardMapping {
+            map_size: 12,
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 69,
+            reference_frequency: 440.0,
+            octave_degree: 12,
+            keys: (0..12).map(Some).collect(),
+        }
+    }
+
+    #[test]
+    fn read_kbm_valid() {
+        let parsed = KeyboardMapping::from_str(
+"! simple.kbm
+!
+12
+0
+127
+60
+69
+440.0
+12
+!
+0
+1
+2
+3
+4
+5
+6
+7
+8
+9
+10
+11"
+        ).unwrap();
+
+        assert_eq!(parsed, twelve_tet_mapping());
+    }
+
+    #[test]
+    fn write_then_read_kbm() {
+        let mapping = twelve_tet_mapping();
+        let written = mapping.to_string();
+        assert_eq!(KeyboardMapping::from_str(&written).unwrap(), mapping);
+    }
+
+    #[test]
+    fn map_key_twelve_tet() {
+        // A 12-tone equal tempered scale, degrees 1..12 above the 1/1.
+        let scale = Scale {
+            description: "12-TET".to_string(),
+            notes: (1..13).map(|i| Note::Cents(100.0 * i as f64)).collect(),
+            ..Default::default()
+        };
+        let mapping = twelve_tet_mapping();
+
+        // A4, the reference note, must come back as the reference frequency.
+        assert!((scale.map_key(&mapping, 69).unwrap() - 440.0).abs() < 1e-9);
+
+        // A5, one formal octave above, is twice the frequency.
+        assert!((scale.map_key(&mapping, 81).unwrap() - 880.0).abs() < 1e-9);
+
+        // A3, one formal octave below, is half the frequency.
+        assert!((scale.map_key(&mapping, 57).unwrap() - 220.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn map_key_out_of_range_or_unmapped() {
+        let scale = Scale {
+            description: "12-TET".to_string(),
+            notes: (1..13).map(|i| Note::Cents(100.0 * i as f64)).collect(),
+            ..Default::default()
+        };
+        let mut mapping = twelve_tet_mapping();
+        mapping.last_note = 72;
+        mapping.keys[5] = None;
+
+        assert_eq!(scale.map_key(&mapping, 127), None);
+        assert_eq!(scale.map_key(&mapping, 65), None);
+    }
+
+    #[test]
+    fn map_key_handles_keys_shorter_than_map_size() {
+        let scale = Scale {
+            description: "12-TET".to_string(),
+            notes: (1..13).map(|i| Note::Cents(100.0 * i as f64)).collect(),
+            ..Default::default()
+        };
+        let mut mapping = twelve_tet_mapping();
+        mapping.keys.truncate(2);
+
+        assert_eq!(scale.map_key(&mapping, 65), None);
+    }
+}
@@ -3,8 +3,91 @@
 
 extern crate num;
 
+pub mod pitch;
+pub mod kbm;
+
+use std::ops::Range;
+
 use num::rational::Ratio;
 
+/// What kind of thing went wrong while parsing a `.scl` file, a `.kbm` file or a single note token.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ParseErrorKind {
+    MissingDescription,
+    MissingNoteCount,
+    BadNoteCount,
+    MissingNote,
+    BadCents,
+    BadRatio,
+    CountMismatch { expected: usize, found: usize },
+    MissingField(&'static str),
+    BadField(&'static str),
+    BadKey,
+    KeyCountMismatch { expected: usize, found: usize },
+}
+
+/// A parse error carrying the 1-based line number and the byte offset range
+/// (within the original input) of the token that caused the problem, so a
+/// caller can underline the exact spot in a malformed file.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub line: usize,
+    pub span: Range<usize>,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        match self.kind {
+            ParseErrorKind::MissingDescription =>
+                write!(f, "line {}: missing description line", self.line),
+            ParseErrorKind::MissingNoteCount =>
+                write!(f, "line {}: missing number of notes line", self.line),
+            ParseErrorKind::BadNoteCount =>
+                write!(f, "line {}, bytes {}..{}: invalid number of notes",
+                    self.line, self.span.start, self.span.end),
+            ParseErrorKind::MissingNote =>
+                write!(f, "line {}: no note on line", self.line),
+            ParseErrorKind::BadCents =>
+                write!(f, "line {}, bytes {}..{}: invalid cents value",
+                    self.line, self.span.start, self.span.end),
+            ParseErrorKind::BadRatio =>
+                write!(f, "line {}, bytes {}..{}: invalid ratio value",
+                    self.line, self.span.start, self.span.end),
+            ParseErrorKind::CountMismatch { expected, found } =>
+                write!(f, "line {}: expected {} notes, found {}", self.line, expected, found),
+            ParseErrorKind::MissingField(field) =>
+                write!(f, "line {}: missing {}", self.line, field),
+            ParseErrorKind::BadField(field) =>
+                write!(f, "line {}, bytes {}..{}: invalid {}",
+                    self.line, self.span.start, self.span.end, field),
+            ParseErrorKind::BadKey =>
+                write!(f, "line {}, bytes {}..{}: invalid mapping key",
+                    self.line, self.span.start, self.span.end),
+            ParseErrorKind::KeyCountMismatch { expected, found } =>
+                write!(f, "line {}: expected {} mapping keys, found {}", self.line, expected, found),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn description(&self) -> &str {
+        match self.kind {
+            ParseErrorKind::MissingDescription => "missing description line",
+            ParseErrorKind::MissingNoteCount => "missing number of notes line",
+            ParseErrorKind::BadNoteCount => "invalid number of notes",
+            ParseErrorKind::MissingNote => "no note on line",
+            ParseErrorKind::BadCents => "invalid cents value",
+            ParseErrorKind::BadRatio => "invalid ratio value",
+            ParseErrorKind::CountMismatch { .. } => "note count mismatch",
+            ParseErrorKind::MissingField(_) => "missing field",
+            ParseErrorKind::BadField(_) => "invalid field",
+            ParseErrorKind::BadKey => "invalid mapping key",
+            ParseErrorKind::KeyCountMismatch { .. } => "mapping key count mismatch",
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Note {
     Cents(f64),
@@ -22,12 +105,16 @@ impl std::fmt::Display for Note {
 }
 
 impl std::str::FromStr for Note {
-    type Err = &'static str;
-    fn from_str(string: &str) -> Result<Note, &'static str> {
+    type Err = ParseError;
+    fn from_str(string: &str) -> Result<Note, ParseError> {
         if string.contains(".") {
             match string.parse::<f64>() {
                 Ok(cents) => Ok(Note::Cents(cents)),
-                Err(_) => Err("error parsing cent value"),
+                Err(_) => Err(ParseError {
+                    kind: ParseErrorKind::BadCents,
+                    line: 1,
+                    span: 0..string.len(),
+                }),
             }
         }
         else {
@@ -35,77 +122,243 @@ impl std::str::FromStr for Note {
                 Ok(ratio) => {
                     Ok(Note::Ratio(ratio))
                 }
-                Err(_) => Err("error parsing ratio value")
+                Err(_) => Err(ParseError {
+                    kind: ParseErrorKind::BadRatio,
+                    line: 1,
+                    span: 0..string.len(),
+                }),
             }
         }
     }
 }
 
-/// The description must hold on a single line and the ratios in the Note::Ratio must be positive
-#[derive(Debug, PartialEq, Clone)]
+/// The description must hold on a single line and the ratios in the Note::Ratio must be positive.
+///
+/// The remaining fields exist so that reading a file and writing it back out
+/// reproduces it faithfully instead of silently relocating its comments.
+/// `leading_comments` holds `!`-prefixed lines (including the leading `!`)
+/// found before the description, and `description_comments` holds those found
+/// between the description and the note count. `comments` anchors every
+/// remaining `!`-prefixed line to the note it preceded: `comments[i]` is the
+/// (possibly empty) run of comment lines written immediately before
+/// `notes[i]`, and `comments[notes.len()]` is any run found after the last
+/// note, so for a parsed `Scale` `comments.len()` is `notes.len() + 1`.
+/// `note_comments` is parallel to `notes`: `note_comments[i]` is whatever
+/// trailing text followed `notes[i]`'s token on its line, since the format
+/// allows (and real files sometimes use) free text there. `Display` treats
+/// missing entries of `comments` or `note_comments` (as in a `Scale` built
+/// with `Default`) as empty.
+///
+/// `filename`, if set, makes `Display` synthesize a leading `! filename`-style
+/// comment the way real Scala files carry one, for `Scale`s that are built
+/// directly rather than parsed. `from_str` never sets it (a parsed file's own
+/// `! filename` line, if any, already round-trips as part of
+/// `leading_comments`), so re-writing a parsed `Scale` never duplicates it.
+#[derive(Debug, PartialEq, Clone, Default)]
 pub struct Scale {
     pub description: String,
     pub notes: Vec<Note>,
+    pub filename: Option<String>,
+    pub leading_comments: Vec<String>,
+    pub description_comments: Vec<String>,
+    pub comments: Vec<Vec<String>>,
+    pub note_comments: Vec<Option<String>>,
 }
 
 impl std::fmt::Display for Scale {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        if let Some(filename) = &self.filename {
+            try!(writeln!(f, "! {}", filename));
+        }
+
+        for comment in &self.leading_comments {
+            try!(writeln!(f, "{}", comment));
+        }
+
         try!(self.description.fmt(f));
         try!(writeln!(f,""));
 
+        for comment in &self.description_comments {
+            try!(writeln!(f, "{}", comment));
+        }
+
         try!(self.notes.len().fmt(f));
-        //try!(writeln!(f,""));
 
-        for note in & self.notes {
+        if let Some(comments) = self.comments.first() {
+            for comment in comments {
+                try!(writeln!(f, ""));
+                try!(write!(f, "{}", comment));
+            }
+        }
+
+        for (i, note) in self.notes.iter().enumerate() {
             try!(writeln!(f,""));
             try!(note.fmt(f));
+            if let Some(Some(comment)) = self.note_comments.get(i) {
+                try!(write!(f, " {}", comment));
+            }
+            if let Some(comments) = self.comments.get(i + 1) {
+                for comment in comments {
+                    try!(writeln!(f, ""));
+                    try!(write!(f, "{}", comment));
+                }
+            }
         }
 
         Ok( () )
     }
 }
 
+/// A single physical line of the input, with its 1-based line number and the
+/// byte offset of its first character, so that sub-ranges within `raw` can be
+/// translated back into offsets within the original file.
+pub(crate) struct Line<'a> {
+    pub(crate) number: usize,
+    pub(crate) start: usize,
+    pub(crate) raw: &'a str,
+}
+
+/// Splits `s` the same way `str::lines` does (on `\n`, stripping a trailing
+/// `\r`, no phantom line for a trailing newline, no lines at all for an empty
+/// string), but keeps the byte offset of each line's first character.
+pub(crate) fn lines_with_positions(s: &str) -> Vec<Line<'_>> {
+    let mut lines = Vec::new();
+    if s.is_empty() {
+        return lines;
+    }
+
+    let mut start = 0;
+    let mut number = 1;
+    let mut rest = s;
+    loop {
+        match rest.find('\n') {
+            Some(idx) => {
+                let with_possible_cr = &rest[..idx];
+                let raw = with_possible_cr.strip_suffix('\r').unwrap_or(with_possible_cr);
+                lines.push(Line { number: number, start: start, raw: raw });
+                start += idx + 1;
+                rest = &rest[idx + 1..];
+                number += 1;
+            },
+            None => {
+                if !rest.is_empty() {
+                    lines.push(Line { number: number, start: start, raw: rest });
+                }
+                break;
+            },
+        }
+    }
+    lines
+}
+
 impl std::str::FromStr for Scale {
-    type Err = &'static str;
-    fn from_str(scale_string: &str) -> Result<Scale, &'static str> {
-        let mut lines_without_comments = scale_string.lines()
-            .filter(|line| !line.starts_with("!"));
-
-        let description = match lines_without_comments.next() {
-            Some(line) => line.to_string(),
-            None => {return Err("couldn't read description line");},
+    type Err = ParseError;
+    fn from_str(scale_string: &str) -> Result<Scale, ParseError> {
+        let mut lines = lines_with_positions(scale_string).into_iter().peekable();
+
+        let mut leading_comments = Vec::new();
+        while let Some(true) = lines.peek().map(|line| line.raw.starts_with("!")) {
+            leading_comments.push(lines.next().unwrap().raw.to_string());
+        }
+
+        let description_line = match lines.next() {
+            Some(line) => line,
+            None => return Err(ParseError {
+                kind: ParseErrorKind::MissingDescription,
+                line: 1,
+                span: 0..0,
+            }),
         };
+        let description = description_line.raw.to_string();
 
-        let mut trimmed_lines = lines_without_comments.map(|line| line.trim());
+        let mut description_comments = Vec::new();
+        while let Some(true) = lines.peek().map(|line| line.raw.starts_with("!")) {
+            description_comments.push(lines.next().unwrap().raw.to_string());
+        }
 
-        let number = match trimmed_lines.next() {
-            Some(line) => match line.parse() {
-                Ok(number) => number,
-                Err(_) => {return Err("invalid number of notes");},
+        let number_line = match lines.next() {
+            Some(line) => line,
+            None => return Err(ParseError {
+                kind: ParseErrorKind::MissingNoteCount,
+                line: description_line.number + 1,
+                span: 0..0,
+            }),
+        };
+        let trimmed_number = number_line.raw.trim();
+        let number: usize = match trimmed_number.parse() {
+            Ok(number) => number,
+            Err(_) => {
+                let leading = number_line.raw.len() - number_line.raw.trim_start().len();
+                let token_start = number_line.start + leading;
+                return Err(ParseError {
+                    kind: ParseErrorKind::BadNoteCount,
+                    line: number_line.number,
+                    span: token_start..(token_start + trimmed_number.len()),
+                });
             },
-            None => {return Err("couldn't read number of notes line");},
         };
 
         let mut notes = Vec::with_capacity(number);
+        let mut note_comments = Vec::with_capacity(number);
+        let mut comments = Vec::new();
+        let mut pending_comments = Vec::new();
+
+        for line in lines {
+            if line.raw.starts_with("!") {
+                pending_comments.push(line.raw.to_string());
+                continue;
+            }
 
-        for line in trimmed_lines {
-            notes.push( match match line.split_whitespace().next() {
-                Some(note_string) => Note::from_str(note_string),
-                None => {return Err("no note on line")},
-            } {
-                Ok(note) => note,
-                Err(message) => {return Err(message)},
-            });
+            comments.push(std::mem::take(&mut pending_comments));
+
+            let trimmed = line.raw.trim();
+            let leading = line.raw.len() - line.raw.trim_start().len();
+            let token_start = line.start + leading;
+
+            let mut token_and_rest = trimmed.splitn(2, char::is_whitespace);
+            let token = match token_and_rest.next() {
+                Some(token) if !token.is_empty() => token,
+                _ => return Err(ParseError {
+                    kind: ParseErrorKind::MissingNote,
+                    line: line.number,
+                    span: token_start..token_start,
+                }),
+            };
+            let note_comment = token_and_rest.next()
+                .map(|rest| rest.trim_start().to_string())
+                .filter(|rest| !rest.is_empty());
+
+            match Note::from_str(token) {
+                Ok(note) => {
+                    notes.push(note);
+                    note_comments.push(note_comment);
+                },
+                Err(inner) => return Err(ParseError {
+                    kind: inner.kind,
+                    line: line.number,
+                    span: token_start..(token_start + token.len()),
+                }),
+            }
         }
+        comments.push(pending_comments);
 
         if notes.len() == number {
             Ok(Scale {
                 description: description,
                 notes: notes,
+                filename: None,
+                leading_comments: leading_comments,
+                description_comments: description_comments,
+                comments: comments,
+                note_comments: note_comments,
             })
         }
         else {
-            Err("number of notes doesn't match actual number of notes")
+            Err(ParseError {
+                kind: ParseErrorKind::CountMismatch { expected: number, found: notes.len() },
+                line: number_line.number,
+                span: 0..0,
+            })
         }
     }
 }
@@ -115,6 +368,8 @@ impl std::str::FromStr for Scale {
 mod tests {
     use Note;
     use Scale;
+    use ParseError;
+    use ParseErrorKind;
     use num::rational::Ratio;
     use std::str::FromStr;
 
@@ -186,6 +441,28 @@ mod tests {
                     Note::Cents(1082.89214),
                     Note::Ratio(Ratio::new(2,1)),
                 ],
+                filename: None,
+                leading_comments: vec!["! meanquar.scl".to_string(), "!".to_string()],
+                description_comments: vec![ ],
+                comments: vec![
+                    vec!["!".to_string()],
+                    vec![ ], vec![ ], vec![ ], vec![ ], vec![ ], vec![ ],
+                    vec![ ], vec![ ], vec![ ], vec![ ], vec![ ], vec![ ],
+                ],
+                note_comments: vec![
+                    None,
+                    None,
+                    None,
+                    Some("writing stuff here should do nothing".to_string()),
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                ],
             }
         );
         assert_eq!(Scale::from_str(
@@ -196,32 +473,45 @@ mod tests {
             Scale{
                 description: "test zero notes".to_string(),
                 notes: vec![ ],
+                filename: None,
+                leading_comments: vec![ ],
+                description_comments: vec![ ],
+                comments: vec![vec!["!".to_string()]],
+                note_comments: vec![ ],
             }
         );
     }
 
     #[test]
     fn read_scale_not_valid() {
-        "".parse::<Scale>().unwrap_err();
-        "!".parse::<Scale>().unwrap_err();
-        "asdfasdf".parse::<Scale>().unwrap_err();
-
-"! 
-! ffdf".parse::<Scale>().unwrap_err();
-
-"ffdf
-asd".parse::<Scale>().unwrap_err();
-
-"ffdf
--2".parse::<Scale>().unwrap_err();
-
-"ffdt
+        assert_eq!("".parse::<Scale>().unwrap_err(),
+            ParseError { kind: ParseErrorKind::MissingDescription, line: 1, span: 0..0 });
+        assert_eq!("!".parse::<Scale>().unwrap_err(),
+            ParseError { kind: ParseErrorKind::MissingDescription, line: 1, span: 0..0 });
+        assert_eq!("asdfasdf".parse::<Scale>().unwrap_err(),
+            ParseError { kind: ParseErrorKind::MissingNoteCount, line: 2, span: 0..0 });
+
+        assert_eq!("!
+! ffdf".parse::<Scale>().unwrap_err(),
+            ParseError { kind: ParseErrorKind::MissingDescription, line: 1, span: 0..0 });
+
+        assert_eq!("ffdf
+asd".parse::<Scale>().unwrap_err(),
+            ParseError { kind: ParseErrorKind::BadNoteCount, line: 2, span: 5..8 });
+
+        assert_eq!("ffdf
+-2".parse::<Scale>().unwrap_err(),
+            ParseError { kind: ParseErrorKind::BadNoteCount, line: 2, span: 5..7 });
+
+        assert_eq!("ffdt
 1
--1/2".parse::<Scale>().unwrap_err();
+-1/2".parse::<Scale>().unwrap_err(),
+            ParseError { kind: ParseErrorKind::BadRatio, line: 3, span: 7..11 });
 
-"ffdt 
+        assert_eq!("ffdt
 0
-1/2".parse::<Scale>().unwrap_err();
+1/2".parse::<Scale>().unwrap_err(),
+            ParseError { kind: ParseErrorKind::CountMismatch { expected: 0, found: 1 }, line: 2, span: 0..0 });
 
     }
 
@@ -244,6 +534,7 @@ asd".parse::<Scale>().unwrap_err();
                     Note::Cents(1082.89214),
                     Note::Ratio(Ratio::new(2,1)),
                 ],
+                ..Default::default()
             }.to_string());
 
         let supposed =
@@ -267,6 +558,56 @@ asd".parse::<Scale>().unwrap_err();
         assert_eq!(written, supposed);
     }
 
+    #[test]
+    fn read_then_write_scale_preserves_comments() {
+        // A comment between the note count and the first note (here, right
+        // after "12") must come back out in that same spot, not get hoisted
+        // up next to the header comments.
+        let original =
+"! meanquar.scl
+!
+1/4-comma meantone scale. Pietro Aaron's temperament (1523)
+12
+!
+76.049
+193.15686
+310.26471
+5/4 writing stuff here should do nothing
+503.42157
+579.47057
+696.57843
+25/16
+889.73529
+1006.84314
+1082.89214
+2/1";
+
+        let scale = Scale::from_str(original).unwrap();
+        assert_eq!(scale.leading_comments, vec!["! meanquar.scl".to_string(), "!".to_string()]);
+        assert_eq!(scale.comments[0], vec!["!".to_string()]);
+        assert_eq!(scale.note_comments[3], Some("writing stuff here should do nothing".to_string()));
+
+        let written = scale.to_string();
+        assert_eq!(written, original);
+        assert_eq!(written.parse::<Scale>().unwrap(), scale);
+    }
+
+    #[test]
+    fn write_scale_synthesizes_filename_header() {
+        let scale = Scale {
+            description: "test".to_string(),
+            notes: vec![Note::Ratio(Ratio::new(2,1))],
+            filename: Some("test.scl".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(scale.to_string(),
+"! test.scl
+test
+1
+2/1");
+    }
+
 
     // TODO at this point quickcheck only generates "well-behaving" values between -100 and 100 so
     // it would be best to check if this works for crazier values as well
@@ -297,6 +638,8 @@ asd".parse::<Scale>().unwrap_err();
 
 
     fn write_then_read_scale(description: String, notes: Vec<Note>) -> bool {
+        let note_comments = vec![None; notes.len()];
+        let comments = vec![Vec::new(); notes.len() + 1];
         let scale = Scale {
             description: {
                 // Description should be only one line and not start with !
@@ -305,6 +648,11 @@ asd".parse::<Scale>().unwrap_err();
                     .filter(|c| *c != '!' && *c != '\r' && *c != '\n').collect()
             },
             notes: notes,
+            filename: None,
+            leading_comments: vec![ ],
+            description_comments: vec![ ],
+            comments: comments,
+            note_comments: note_comments,
         };
 
         let string = scale.to_string();
@@ -0,0 +1,99 @@
+//! Turns a parsed `Scale` into actual pitches, in Hz.
+
+use Note;
+use Scale;
+
+impl Note {
+    /// This note's size, in cents above the `1/1`.
+    pub fn as_cents(&self) -> f64 {
+        match self {
+            &Note::Cents(cents) => cents,
+            &Note::Ratio(ratio) => 1200.0 * (*ratio.numer() as f64 / *ratio.denom() as f64).log2(),
+        }
+    }
+
+    /// This note's size expressed as a frequency ratio, e.g. `2.0` for an octave.
+    pub fn as_ratio_f64(&self) -> f64 {
+        match self {
+            &Note::Cents(cents) => 2f64.powf(cents / 1200.0),
+            &Note::Ratio(ratio) => *ratio.numer() as f64 / *ratio.denom() as f64,
+        }
+    }
+}
+
+impl Scale {
+    /// The frequency, in Hz, of every note in the scale (not counting the
+    /// implicit `1/1`), given the frequency of the scale's `1/1` degree.
+    pub fn frequencies(&self, base_hz: f64) -> Vec<f64> {
+        self.notes.iter().map(|note| base_hz * note.as_ratio_f64()).collect()
+    }
+
+    /// The frequency, in Hz, of `degree` steps above the scale's `1/1`.
+    ///
+    /// Degrees beyond the notes listed in the file wrap around by repeating
+    /// the scale's period (its last note, conventionally the octave `2/1`)
+    /// as many times as needed; degrees below `0` wrap downward the same way.
+    pub fn frequency_at(&self, degree: i32, base_hz: f64) -> f64 {
+        let len = self.notes.len() as i32;
+        if len == 0 {
+            return base_hz;
+        }
+
+        let period = self.notes[self.notes.len() - 1].as_ratio_f64();
+
+        let within_degree = ((degree % len) + len) % len;
+        let periods = (degree - within_degree) / len;
+
+        let within_ratio = if within_degree == 0 {
+            1.0
+        }
+        else {
+            self.notes[(within_degree - 1) as usize].as_ratio_f64()
+        };
+
+        base_hz * within_ratio * period.powi(periods)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Note;
+    use Scale;
+    use num::rational::Ratio;
+
+    #[test]
+    fn note_as_cents_and_ratio() {
+        assert_eq!(Note::Cents(1200.0).as_cents(), 1200.0);
+        assert!((Note::Ratio(Ratio::new(2,1)).as_cents() - 1200.0).abs() < 1e-9);
+        assert!((Note::Ratio(Ratio::new(3,2)).as_cents() - 701.9550008653874).abs() < 1e-9);
+
+        assert!((Note::Cents(1200.0).as_ratio_f64() - 2.0).abs() < 1e-9);
+        assert_eq!(Note::Ratio(Ratio::new(3,2)).as_ratio_f64(), 1.5);
+    }
+
+    #[test]
+    fn scale_frequencies() {
+        let scale = Scale {
+            description: "test".to_string(),
+            notes: vec![Note::Ratio(Ratio::new(3,2)), Note::Ratio(Ratio::new(2,1))],
+            ..Default::default()
+        };
+
+        assert_eq!(scale.frequencies(100.0), vec![150.0, 200.0]);
+    }
+
+    #[test]
+    fn scale_frequency_at_wraps_by_period() {
+        let scale = Scale {
+            description: "test".to_string(),
+            notes: vec![Note::Ratio(Ratio::new(3,2)), Note::Ratio(Ratio::new(2,1))],
+            ..Default::default()
+        };
+
+        assert_eq!(scale.frequency_at(0, 100.0), 100.0);
+        assert_eq!(scale.frequency_at(1, 100.0), 150.0);
+        assert_eq!(scale.frequency_at(2, 100.0), 200.0);
+        assert_eq!(scale.frequency_at(3, 100.0), 300.0);
+        assert_eq!(scale.frequency_at(-2, 100.0), 50.0);
+    }
+}